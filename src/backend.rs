@@ -0,0 +1,372 @@
+//! Transport backends used to actually route traffic from the remote host
+//! back to us.
+//!
+//! The original (and default) backend installs an NFTables DNAT rule on the
+//! remote, which needs `nft` present and a root-ish user. The SSH backend
+//! instead leans on openssh's own remote port forwarding so unprivileged users
+//! on hosts without `nft` can still expose a service, and nothing has to be
+//! cleaned out of the firewall if we die unexpectedly.
+
+use std::process::Stdio;
+
+use color_eyre::eyre::ContextCompat;
+use openssh::Session;
+use tokio::io::AsyncWriteExt;
+use tracing::Instrument;
+
+use crate::Params;
+
+/// A way of getting packets arriving on the remote back to `local`.
+#[async_trait::async_trait]
+pub trait Backend: Send + Sync {
+    /// Install whatever the backend needs on the remote to start forwarding.
+    async fn setup(&self, p: &Params, s: &Session) -> color_eyre::Result<()>;
+
+    /// Confirm the forward is still in place, erroring if it has gone away.
+    async fn check(&self, p: &Params, s: &Session) -> color_eyre::Result<()>;
+
+    /// Remove anything `setup` installed. Best-effort: called on shutdown.
+    async fn teardown(&self, p: &Params, s: &Session) -> color_eyre::Result<()>;
+}
+
+/// Resolve the backend for a set of params, probing the remote if the user
+/// asked us to auto-detect.
+pub async fn resolve(p: &Params, s: &Session) -> color_eyre::Result<Box<dyn Backend>> {
+    let kind = match p.backend {
+        BackendKind::Auto => detect(s).await?,
+        other => other,
+    };
+
+    Ok(build(kind))
+}
+
+fn build(kind: BackendKind) -> Box<dyn Backend> {
+    match kind {
+        BackendKind::Nft => Box::new(NftBackend),
+        BackendKind::Iptables => Box::new(IptablesBackend),
+        BackendKind::Ssh => Box::new(SshForwardBackend::default()),
+        // `resolve` collapses Auto to a concrete backend before we get here.
+        BackendKind::Auto => Box::new(NftBackend),
+    }
+}
+
+/// Probe the remote once for the firewall tooling it actually has.
+async fn detect(s: &Session) -> color_eyre::Result<BackendKind> {
+    let span = tracing::info_span!("detecting remote backend");
+
+    let out = s
+        .command("sh")
+        .arg("-c")
+        .arg("command -v nft || command -v iptables")
+        .output()
+        .instrument(span)
+        .await?;
+
+    let found = String::from_utf8_lossy(&out.stdout);
+    if found.contains("nft") {
+        Ok(BackendKind::Nft)
+    } else if found.contains("iptables") {
+        Ok(BackendKind::Iptables)
+    } else {
+        Err(color_eyre::eyre::eyre!(
+            "Neither nft nor iptables found on remote; pass --backend ssh"
+        ))
+    }
+}
+
+#[derive(clap::ArgEnum, serde::Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[clap(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum BackendKind {
+    /// Install an NFTables DNAT rule (needs `nft` and root on the remote).
+    Nft,
+    /// Install an iptables DNAT rule (needs `iptables` and root on the remote).
+    Iptables,
+    /// Forward over the SSH connection itself (no firewall, no root).
+    ///
+    /// TCP only, and the remote `sshd` must allow public remote-forward binds
+    /// (`GatewayPorts yes` or `clientspecified`); with the default
+    /// `GatewayPorts no` the forward binds loopback-only and the service stays
+    /// private.
+    Ssh,
+    /// Probe the remote and pick nft or iptables, whichever is present.
+    #[default]
+    Auto,
+}
+
+/// The classic backend: a DNAT + masquerade table installed via `nft -f -`.
+pub struct NftBackend;
+
+fn nft_rule(p: &Params) -> String {
+    format!(
+        r#"
+table ip {identifier}
+delete table {identifier}
+table ip {identifier} {{
+        chain prerouting {{
+                type nat hook prerouting priority dstnat; policy accept;
+                {mode} dport {remote_port} dnat to {local}
+        }}
+
+        chain postrouting {{
+                type nat hook postrouting priority srcnat; policy accept;
+                masquerade
+        }}
+}}
+"#,
+        identifier = p.identifier,
+        mode = p.mode.name(),
+        remote_port = p.remote,
+        local = p.local
+    )
+}
+
+#[async_trait::async_trait]
+impl Backend for NftBackend {
+    async fn setup(&self, p: &Params, s: &Session) -> color_eyre::Result<()> {
+        let rule = nft_rule(p);
+
+        let span = tracing::info_span!("installing rule", %rule);
+
+        let mut r = s
+            .command("nft")
+            .args(["-f", "-"])
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        let stdin = r
+            .stdin()
+            .as_mut()
+            .wrap_err("Didn't get a stdin for some reason")?;
+        stdin.write_all(rule.as_bytes()).await?;
+        stdin.shutdown().await?;
+
+        let out = r.wait_with_output().instrument(span).await?;
+
+        if !out.status.success() {
+            return Err(color_eyre::eyre::eyre!(
+                "Installing redirect failed: {}",
+                std::str::from_utf8(&out.stderr)?
+            ));
+        }
+
+        Ok(())
+    }
+
+    async fn check(&self, p: &Params, s: &Session) -> color_eyre::Result<()> {
+        let span = tracing::debug_span!("Checking rule", rule = %p.identifier);
+
+        let exists = s
+            .command("nft")
+            .args(["list", "table"])
+            .arg(&p.identifier)
+            .status()
+            .instrument(span)
+            .await?
+            .success();
+
+        if !exists {
+            return Err(color_eyre::eyre::eyre!("Rule got dropped for some reason"));
+        }
+
+        Ok(())
+    }
+
+    async fn teardown(&self, p: &Params, s: &Session) -> color_eyre::Result<()> {
+        let span = tracing::info_span!("Deleting rule", rule = %p.identifier);
+
+        s.command("nft")
+            .args(["delete", "table"])
+            .arg(&p.identifier)
+            .status()
+            .instrument(span)
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// The same DNAT + masquerade intent expressed through iptables, for hosts
+/// that ship iptables but not `nft`.
+///
+/// Every rule carries a comment derived from the identifier so `check` and
+/// `teardown` can match exactly the rules this instance installed.
+pub struct IptablesBackend;
+
+impl IptablesBackend {
+    fn comment(p: &Params) -> String {
+        format!("easy-expose:{}", p.identifier)
+    }
+
+    /// The PREROUTING DNAT rule spec, minus the `-A`/`-C`/`-D` verb.
+    fn dnat_spec(p: &Params) -> Vec<String> {
+        vec![
+            "-t".into(),
+            "nat".into(),
+            "PREROUTING".into(),
+            "-p".into(),
+            p.mode.name().into(),
+            "--dport".into(),
+            p.remote.to_string(),
+            "-m".into(),
+            "comment".into(),
+            "--comment".into(),
+            Self::comment(p),
+            "-j".into(),
+            "DNAT".into(),
+            "--to-destination".into(),
+            p.local.to_string(),
+        ]
+    }
+
+    /// The POSTROUTING MASQUERADE rule spec, minus the verb.
+    fn masquerade_spec(p: &Params) -> Vec<String> {
+        vec![
+            "-t".into(),
+            "nat".into(),
+            "POSTROUTING".into(),
+            "-p".into(),
+            p.mode.name().into(),
+            "-d".into(),
+            p.local.ip().to_string(),
+            "--dport".into(),
+            p.local.port().to_string(),
+            "-m".into(),
+            "comment".into(),
+            "--comment".into(),
+            Self::comment(p),
+            "-j".into(),
+            "MASQUERADE".into(),
+        ]
+    }
+
+    async fn run(s: &Session, verb: &str, spec: &[String]) -> color_eyre::Result<bool> {
+        Ok(s.command("iptables")
+            .arg(verb)
+            .args(spec)
+            .status()
+            .await?
+            .success())
+    }
+}
+
+#[async_trait::async_trait]
+impl Backend for IptablesBackend {
+    async fn setup(&self, p: &Params, s: &Session) -> color_eyre::Result<()> {
+        let span = tracing::info_span!("installing iptables rules", rule = %Self::comment(p));
+
+        async {
+            for spec in [Self::dnat_spec(p), Self::masquerade_spec(p)] {
+                // `inner` can re-enter setup after any transient failure, so
+                // clear out every copy a previous attempt left behind before
+                // appending — otherwise identical rules stack up and the single
+                // `-D` in teardown only peels off one. Matches the
+                // `delete table; table` idempotence NftBackend relies on.
+                while Self::run(s, "-D", &spec).await? {}
+
+                if !Self::run(s, "-A", &spec).await? {
+                    return Err(color_eyre::eyre::eyre!("Installing iptables rule failed"));
+                }
+            }
+            Ok(())
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn check(&self, p: &Params, s: &Session) -> color_eyre::Result<()> {
+        let span = tracing::debug_span!("Checking rule", rule = %Self::comment(p));
+
+        let exists = Self::run(s, "-C", &Self::dnat_spec(p)).instrument(span).await?;
+
+        if !exists {
+            return Err(color_eyre::eyre::eyre!("Rule got dropped for some reason"));
+        }
+
+        Ok(())
+    }
+
+    async fn teardown(&self, p: &Params, s: &Session) -> color_eyre::Result<()> {
+        let span = tracing::info_span!("Deleting rule", rule = %Self::comment(p));
+
+        async {
+            for spec in [Self::dnat_spec(p), Self::masquerade_spec(p)] {
+                let _ = Self::run(s, "-D", &spec).await?;
+            }
+            Ok(())
+        }
+        .instrument(span)
+        .await
+    }
+}
+
+/// Forward using SSH's own remote port forwarding.
+///
+/// The remote host binds a listening socket on `remote` and openssh streams
+/// every accepted connection back down the existing session to `local`; no
+/// DNAT rule is touched so nothing leaks if we die.
+///
+/// Two caveats, both enforced/surfaced in [`setup`](Self::setup):
+///
+/// * **Public binds need `sshd` cooperation.** OpenSSH's default
+///   `GatewayPorts no` silently downgrades a remote-forward bind to loopback
+///   regardless of the requested address, so the service would only be
+///   reachable from the VPS itself. The remote `sshd` must be configured with
+///   `GatewayPorts yes` (or `clientspecified`); we log a reminder on setup.
+/// * **TCP only.** SSH remote forwarding (`request_port_forward` with
+///   [`ForwardType::Remote`](openssh::ForwardType::Remote)) is stream-only, so
+///   there is no channel over which to carry UDP datagrams. This is a
+///   deliberate scope reduction from the original request's framed-datagram
+///   idea; UDP forwards must use the `nft` or `iptables` backend.
+#[derive(Default)]
+pub struct SshForwardBackend;
+
+#[async_trait::async_trait]
+impl Backend for SshForwardBackend {
+    async fn setup(&self, p: &Params, s: &Session) -> color_eyre::Result<()> {
+        use openssh::{ForwardType, Socket};
+        use std::net::{Ipv4Addr, SocketAddr};
+
+        if p.mode == crate::L4Mode::Udp {
+            return Err(color_eyre::eyre::eyre!(
+                "The ssh backend only forwards TCP; SSH remote forwarding has no \
+                 UDP channel. Use --backend nft or --backend iptables for UDP."
+            ));
+        }
+
+        let remote_bind = SocketAddr::from((Ipv4Addr::UNSPECIFIED, p.remote));
+
+        // A public bind only takes effect if the remote sshd opted in; with the
+        // default GatewayPorts the kernel hands us loopback and the exposure
+        // silently fails, so make the requirement visible.
+        tracing::info!(
+            "ssh backend binds {remote_bind} on the remote; this is only reachable \
+             publicly if the remote sshd has `GatewayPorts yes` (or `clientspecified`)"
+        );
+
+        let span = tracing::info_span!("requesting remote forward", remote = %remote_bind, local = %p.local);
+        s.request_port_forward(
+            ForwardType::Remote,
+            Socket::from(remote_bind),
+            Socket::from(p.local),
+        )
+        .instrument(span)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn check(&self, _p: &Params, s: &Session) -> color_eyre::Result<()> {
+        // The forward lives inside the SSH session, so a healthy session means
+        // a healthy forward.
+        s.check().await?;
+
+        Ok(())
+    }
+
+    async fn teardown(&self, _p: &Params, _s: &Session) -> color_eyre::Result<()> {
+        Ok(())
+    }
+}
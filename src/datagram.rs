@@ -0,0 +1,53 @@
+//! Length-prefixed datagram framing used to carry UDP over the
+//! stream-oriented QUIC data plane.
+//!
+//! Each datagram is written as a big-endian `u16` length followed by that many
+//! bytes of payload, so a reader can recover packet boundaries from a byte
+//! stream.
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Write a single datagram, prefixed with its big-endian `u16` length.
+pub async fn write_datagram<W: AsyncWrite + Unpin>(w: &mut W, buf: &[u8]) -> std::io::Result<()> {
+    let len = u16::try_from(buf.len())
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "datagram too large"))?;
+    w.write_u16(len).await?;
+    w.write_all(buf).await?;
+    Ok(())
+}
+
+/// Read a single length-prefixed datagram into a freshly allocated buffer.
+pub async fn read_datagram<R: AsyncRead + Unpin>(r: &mut R) -> std::io::Result<Vec<u8>> {
+    let len = r.read_u16().await? as usize;
+    let mut buf = vec![0u8; len];
+    r.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn round_trips_a_datagram() {
+        let payload = b"hello, datagram";
+
+        let mut wire = Vec::new();
+        write_datagram(&mut wire, payload).await.unwrap();
+        // 2-byte big-endian length prefix, then the payload verbatim.
+        assert_eq!(wire.len(), 2 + payload.len());
+        assert_eq!(&wire[..2], &(payload.len() as u16).to_be_bytes());
+
+        let mut reader = wire.as_slice();
+        let got = read_datagram(&mut reader).await.unwrap();
+        assert_eq!(got, payload);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_datagram_that_overflows_the_prefix() {
+        let oversized = vec![0u8; usize::from(u16::MAX) + 1];
+        let mut wire = Vec::new();
+        let err = write_datagram(&mut wire, &oversized).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,44 @@
+//! The `local-to-remote` direction: bind a port on this machine and tunnel its
+//! traffic over the SSH session to a service only the remote host can reach.
+//!
+//! This is the mirror image of the exposing path — no firewall rule is
+//! involved, we just lean on SSH's local forwarding. Only TCP is supported:
+//! SSH local forwarding (`request_port_forward` with
+//! [`ForwardType::Local`](openssh::ForwardType::Local)) delivers a stream, and
+//! there is no component on the remote to turn that stream back into datagrams
+//! aimed at a UDP service, so UDP is rejected up front.
+
+use std::net::{Ipv4Addr, SocketAddr};
+
+use openssh::Session;
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+
+use crate::{L4Mode, Params};
+
+/// Forward a local port to the remote's target until cancelled.
+pub async fn run(p: &Params, s: &Session, cancel: &CancellationToken) -> color_eyre::Result<()> {
+    use openssh::{ForwardType, Socket};
+
+    if p.mode == L4Mode::Udp {
+        return Err(color_eyre::eyre::eyre!(
+            "local-to-remote forwarding only supports TCP; SSH local forwarding \
+             cannot deliver UDP datagrams to a remote UDP service."
+        ));
+    }
+
+    let local_bind = SocketAddr::from((Ipv4Addr::UNSPECIFIED, p.remote));
+
+    let span = tracing::info_span!("local forward", bind = %local_bind, target = %p.local);
+    s.request_port_forward(
+        ForwardType::Local,
+        Socket::from(local_bind),
+        Socket::from(p.local),
+    )
+    .instrument(span)
+    .await?;
+
+    cancel.cancelled().await;
+
+    Ok(())
+}
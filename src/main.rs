@@ -1,16 +1,46 @@
-use clap::{ArgEnum, Parser};
-use color_eyre::eyre::ContextCompat;
+use clap::{ArgEnum, Parser, Subcommand};
 use core_extensions::ToTime;
 use openssh::Session;
-use std::{net::SocketAddr, path::PathBuf, process::Stdio, sync::atomic::AtomicBool};
-use tokio::io::AsyncWriteExt;
+use serde::Deserialize;
+use std::{net::SocketAddr, path::PathBuf};
+use tokio_util::sync::CancellationToken;
 use tracing::Instrument;
 
-static CANCELLED: AtomicBool = AtomicBool::new(false);
+mod backend;
+mod datagram;
+mod local_forward;
+mod quic;
 
-#[derive(ArgEnum, Clone, Copy, PartialEq, Eq, Debug)]
+use backend::BackendKind;
+
+/// How the data plane gets traffic between the remote and us.
+#[derive(ArgEnum, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
 #[clap(rename_all = "snake_case")]
-enum L4Mode {
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    /// Let the backend route traffic (DNAT, or SSH port forwarding).
+    #[default]
+    Direct,
+    /// Tunnel over QUIC for clients that aren't routable from the remote.
+    Quic,
+}
+
+/// Which way traffic flows through the tunnel.
+#[derive(ArgEnum, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[clap(rename_all = "kebab-case")]
+#[serde(rename_all = "kebab-case")]
+pub enum Direction {
+    /// Expose `local` on the remote's `remote` port (packets arrive on the VPS).
+    #[default]
+    RemoteToLocal,
+    /// Bind `remote` locally and tunnel to `local` reached from the remote host.
+    LocalToRemote,
+}
+
+#[derive(ArgEnum, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[clap(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum L4Mode {
     Udp,
     Tcp,
 }
@@ -30,12 +60,39 @@ fn do_socketaddr(s: &str) -> Result<SocketAddr, std::io::Error> {
     Ok(s.to_socket_addrs()?.next().unwrap())
 }
 
-/// Set up a packet redirect on some remote host that forwards packets to you
-///
-/// example: `easy_expose test_redir tcp root@vps 9912 100.82.95.116:9912`
+/// Expose local services on remote hosts over SSH
 #[derive(Parser, Debug)]
 #[clap(about, version, author)]
-struct Params {
+struct Cli {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Expose a single service (the classic one-shot form)
+    Add(Params),
+
+    /// Run many named forwards from a config file, supervised in one process
+    Run(RunArgs),
+
+    /// Internal: the remote-side QUIC listener, launched over SSH
+    #[clap(hide = true)]
+    QuicServe(quic::ServeArgs),
+}
+
+#[derive(Parser, Debug)]
+struct RunArgs {
+    /// A TOML or JSON config describing the forwards to run
+    #[clap(short, long, parse(from_os_str), value_name = "FILE")]
+    config: PathBuf,
+}
+
+/// A single forwarding instance.
+///
+/// example: `easy_expose add test_redir tcp root@vps 9912 100.82.95.116:9912`
+#[derive(Parser, Deserialize, Debug)]
+pub struct Params {
     /// A unique name to identify this forwarding instance
     identifier: String,
 
@@ -51,8 +108,27 @@ struct Params {
 
     /// The ssh identity file to use
     #[clap(short, long, parse(from_os_str), value_name = "FILE")]
+    #[serde(default)]
     identity: Option<PathBuf>,
 
+    /// How to route traffic back from the remote
+    ///
+    /// Defaults to probing the remote for `nft`/`iptables`; pass an explicit
+    /// value to pin a backend (including `ssh` for the firewall-free path).
+    #[clap(long, arg_enum, default_value_t = BackendKind::Auto)]
+    #[serde(default)]
+    backend: BackendKind,
+
+    /// Which way traffic flows through the tunnel
+    #[clap(long, arg_enum, default_value_t = Direction::RemoteToLocal)]
+    #[serde(default)]
+    direction: Direction,
+
+    /// The data-plane transport to use
+    #[clap(long, arg_enum, default_value_t = Transport::Direct)]
+    #[serde(default)]
+    transport: Transport,
+
     /// The remote port to expose on
     //#[clap(short, long)]
     remote: u16,
@@ -62,6 +138,28 @@ struct Params {
     local: SocketAddr,
 }
 
+/// The set of forwards read from a `run --config` file.
+#[derive(Deserialize, Debug)]
+struct Config {
+    #[serde(rename = "forward")]
+    forwards: Vec<Params>,
+}
+
+impl Config {
+    fn load(path: &std::path::Path) -> color_eyre::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        let is_json = path.extension().map_or(false, |e| e == "json");
+
+        let config = if is_json {
+            serde_json::from_str(&text)?
+        } else {
+            toml::from_str(&text)?
+        };
+
+        Ok(config)
+    }
+}
+
 async fn open_ssh(p: &Params) -> color_eyre::Result<Session> {
     use openssh::SessionBuilder;
 
@@ -76,120 +174,42 @@ async fn open_ssh(p: &Params) -> color_eyre::Result<Session> {
     Ok(s.connect(&p.destination).instrument(span).await?)
 }
 
-fn nft_rule(p: &Params) -> String {
-    format!(
-        r#"
-table ip {identifier}
-delete table {identifier}
-table ip {identifier} {{
-        chain prerouting {{
-                type nat hook prerouting priority dstnat; policy accept;
-                {mode} dport {remote_port} dnat to {local}
-        }}
-
-        chain postrouting {{
-                type nat hook postrouting priority srcnat; policy accept;
-                masquerade
-        }}
-}}
-"#,
-        identifier = p.identifier,
-        mode = p.mode.name(),
-        remote_port = p.remote,
-        local = p.local
-    )
-}
-
-async fn setup_redirect(p: &Params, s: &Session) -> color_eyre::Result<()> {
-    let rule = nft_rule(p);
-
-    let span = tracing::info_span!("installing rule", %rule);
-
-    let mut r = s
-        .command("nft")
-        .args(["-f", "-"])
-        .stdin(Stdio::piped())
-        .stderr(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()?;
-
-    let stdin = r
-        .stdin()
-        .as_mut()
-        .wrap_err("Didn't get a stdin for some reason")?;
-    stdin.write_all(rule.as_bytes()).await?;
-    stdin.shutdown().await?;
-
-    let out = r.wait_with_output().instrument(span).await?;
-
-    if !out.status.success() {
-        return Err(color_eyre::eyre::eyre!(
-            "Installing redirect failed: {}",
-            std::str::from_utf8(&out.stderr)?
-        ));
-    }
-
-    Ok(())
-}
-
-async fn check_rule(p: &Params, s: &Session) -> color_eyre::Result<()> {
-    let span = tracing::debug_span!("Checking rule", rule = %p.identifier);
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
 
-    let exists = s
-        .command("nft")
-        .args(["list", "table"])
-        .arg(&p.identifier)
-        .status()
-        .instrument(span)
-        .await?
-        .success();
+    let mut sigint = signal(SignalKind::interrupt()).unwrap();
+    let mut sigterm = signal(SignalKind::terminate()).unwrap();
 
-    if !exists {
-        return Err(color_eyre::eyre::eyre!("Rule got dropped for some reason"));
+    tokio::select! {
+        _ = sigint.recv() => {}
+        _ = sigterm.recv() => {}
     }
-
-    Ok(())
 }
 
-async fn delete_rule(p: &Params, s: &Session) -> color_eyre::Result<()> {
-    let span = tracing::info_span!("Deleting rule", rule = %p.identifier);
-
-    s.command("nft")
-        .args(["delete", "table"])
-        .arg(&p.identifier)
-        .status()
-        .instrument(span)
-        .await?;
-
-    Ok(())
-}
-
-async fn wait_for_quit() {
-    use tokio::signal::unix::{signal, SignalKind};
-
-    let mut sigint = signal(SignalKind::terminate()).unwrap();
-    let mut sigterm = signal(SignalKind::interrupt()).unwrap();
-
-    tokio::select! {
-        _ = sigint.recv() => {
-            CANCELLED.store(true, std::sync::atomic::Ordering::SeqCst);
-        }
+async fn inner(p: &Params, cancel: &CancellationToken) -> color_eyre::Result<()> {
+    // The QUIC transport dials out and multiplexes streams rather than relying
+    // on the remote routing packets to us.
+    if p.transport == Transport::Quic {
+        let s = open_ssh(p).await?;
+        return quic::run(p, &s, cancel).await;
+    }
 
-        _ = sigterm.recv() => {
-            CANCELLED.store(true, std::sync::atomic::Ordering::SeqCst);
-        }
+    // The local-to-remote direction never touches the firewall, so it bypasses
+    // the backend setup/check/delete flow entirely.
+    if p.direction == Direction::LocalToRemote {
+        let s = open_ssh(p).await?;
+        return local_forward::run(p, &s, cancel).await;
     }
-}
 
-async fn inner(p: &Params) -> color_eyre::Result<()> {
     let inner = || async {
         let s = open_ssh(p).await?;
+        let backend = backend::resolve(p, &s).await?;
 
-        setup_redirect(p, &s).await?;
+        backend.setup(p, &s).await?;
 
         loop {
             tokio::time::sleep(1.minutes()).await;
-            check_rule(p, &s).await?;
+            backend.check(p, &s).await?;
         }
     };
 
@@ -198,22 +218,23 @@ async fn inner(p: &Params) -> color_eyre::Result<()> {
             return r;
         }
 
-        _ = wait_for_quit() => {}
+        _ = cancel.cancelled() => {}
     };
 
     let s = open_ssh(p).await?;
+    let backend = backend::resolve(p, &s).await?;
     // if we get here we need to clean up
-    delete_rule(p, &s).await?;
+    backend.teardown(p, &s).await?;
 
     Ok(())
 }
 
-async fn main_loop(p: &Params) {
+async fn main_loop(p: &Params, cancel: CancellationToken) {
     loop {
-        if let Err(e) = inner(p).await {
+        if let Err(e) = inner(p, &cancel).await {
             tracing::error!(reason = ?e, "Something broke, retrying in 10 seconds");
 
-            if CANCELLED.load(std::sync::atomic::Ordering::Relaxed) {
+            if cancel.is_cancelled() {
                 return;
             }
 
@@ -223,7 +244,7 @@ async fn main_loop(p: &Params) {
             tokio::select! {
                 _ = &mut delay => {}
 
-                _ = wait_for_quit() => {
+                _ = cancel.cancelled() => {
                     return;
                 }
             }
@@ -233,12 +254,46 @@ async fn main_loop(p: &Params) {
     }
 }
 
+/// Supervise every forward concurrently until a signal arrives, then let them
+/// all tear their rules down in parallel before returning.
+async fn supervise(forwards: Vec<Params>) {
+    let root = CancellationToken::new();
+
+    let handles: Vec<_> = forwards
+        .into_iter()
+        .map(|p| {
+            let cancel = root.child_token();
+            let span = tracing::info_span!("forward", identifier = %p.identifier);
+            tokio::spawn(async move { main_loop(&p, cancel).await }.instrument(span))
+        })
+        .collect();
+
+    {
+        let root = root.clone();
+        tokio::spawn(async move {
+            wait_for_signal().await;
+            tracing::info!("Shutting down, tearing down forwards");
+            root.cancel();
+        });
+    }
+
+    for h in handles {
+        let _ = h.await;
+    }
+}
+
 fn install_tracing() -> color_eyre::Result<()> {
     use tracing_subscriber::fmt::format::FmtSpan;
     use tracing_subscriber::layer::SubscriberExt;
     use tracing_subscriber::util::SubscriberInitExt;
 
-    let fmt_layer = tracing_subscriber::fmt::layer().with_span_events(FmtSpan::CLOSE);
+    // Log to stderr: the remote `quic-serve` process hands its chosen port back
+    // to the client on stdout, so traces mustn't share that pipe (otherwise
+    // they'd wedge the port handshake and eventually block forwarding when the
+    // unread pipe fills).
+    let fmt_layer = tracing_subscriber::fmt::layer()
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(std::io::stderr);
     // .pretty();
     let filter_layer = tracing_subscriber::EnvFilter::from_default_env()
         .add_directive("easy_expose=debug".parse()?);
@@ -254,13 +309,70 @@ fn install_tracing() -> color_eyre::Result<()> {
 
 #[tokio::main]
 async fn main() -> color_eyre::Result<()> {
-    let params = Params::parse();
+    let cli = Cli::parse();
 
     install_tracing()?;
 
     color_eyre::install()?;
 
-    main_loop(&params).await;
+    let forwards = match cli.command {
+        Command::Add(p) => vec![p],
+        Command::Run(args) => Config::load(&args.config)?.forwards,
+        Command::QuicServe(args) => return quic::serve(args).await,
+    };
+
+    supervise(forwards).await;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_toml_config() {
+        let path = std::env::temp_dir().join("easy_expose_test_forwards.toml");
+        std::fs::write(
+            &path,
+            r#"
+[[forward]]
+identifier = "web"
+mode = "tcp"
+destination = "root@vps"
+remote = 8080
+local = "127.0.0.1:80"
+"#,
+        )
+        .unwrap();
+
+        let config = Config::load(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(config.forwards.len(), 1);
+        let f = &config.forwards[0];
+        assert_eq!(f.identifier, "web");
+        assert_eq!(f.remote, 8080);
+        assert_eq!(f.mode, L4Mode::Tcp);
+        // Unspecified knobs fall back to their serde defaults.
+        assert_eq!(f.backend, BackendKind::Auto);
+        assert_eq!(f.direction, Direction::RemoteToLocal);
+    }
+
+    #[test]
+    fn loads_json_config() {
+        let path = std::env::temp_dir().join("easy_expose_test_forwards.json");
+        std::fs::write(
+            &path,
+            r#"{"forward":[{"identifier":"dns","mode":"udp","destination":"root@vps","remote":5353,"local":"10.0.0.2:53"}]}"#,
+        )
+        .unwrap();
+
+        let config = Config::load(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(config.forwards.len(), 1);
+        assert_eq!(config.forwards[0].mode, L4Mode::Udp);
+        assert_eq!(config.forwards[0].remote, 5353);
+    }
+}
@@ -0,0 +1,511 @@
+//! A QUIC data-plane for clients that aren't routable from the VPS.
+//!
+//! Instead of DNATing public traffic straight at the client, the easy-expose
+//! process dials *out* to a small listener we launch on the remote over the
+//! existing SSH session. The remote accepts public connections on `remote` and
+//! multiplexes each one back as a QUIC bi-directional stream; this side picks
+//! the stream up and dials `local`.
+//!
+//! There's no CA anywhere: at startup we mint an ephemeral self-signed cert
+//! with rcgen, ship it to the remote to serve, and pin its fingerprint so the
+//! peer is accepted only if it presents exactly that certificate.
+
+use std::net::SocketAddr;
+use std::process::Stdio;
+use std::sync::Arc;
+
+use color_eyre::eyre::ContextCompat;
+use openssh::Session;
+use quinn::{ClientConfig, Connection, Endpoint, ServerConfig, TransportConfig};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+
+use crate::datagram::{read_datagram, write_datagram};
+use crate::{L4Mode, Params};
+
+/// The TLS server name we connect under; the cert is pinned so the value
+/// itself doesn't matter.
+const SERVER_NAME: &str = "easy-expose";
+
+/// How often to send a keepalive so NAT mappings (and the connection) survive
+/// idle periods.
+fn keep_alive() -> TransportConfig {
+    let mut t = TransportConfig::default();
+    t.keep_alive_interval(Some(std::time::Duration::from_secs(15)));
+    t
+}
+
+/// An ephemeral self-signed certificate, plus the DER encoding its pinning
+/// fingerprint is computed from.
+struct Cert {
+    cert_pem: String,
+    key_pem: String,
+    der: Vec<u8>,
+}
+
+fn generate_cert() -> color_eyre::Result<Cert> {
+    let cert = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()])?;
+    Ok(Cert {
+        cert_pem: cert.serialize_pem()?,
+        key_pem: cert.serialize_private_key_pem(),
+        der: cert.serialize_der()?,
+    })
+}
+
+fn fingerprint(der: &[u8]) -> [u8; 32] {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(der).into()
+}
+
+/// A [`rustls::client::ServerCertVerifier`] that accepts exactly one cert,
+/// identified by its SHA-256 fingerprint.
+struct PinnedCert {
+    fingerprint: [u8; 32],
+}
+
+impl rustls::client::ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        if fingerprint(&end_entity.0) == self.fingerprint {
+            Ok(rustls::client::ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+}
+
+/// Run the QUIC client half: launch the remote listener, connect to it, and
+/// forward every inbound stream to `local` until cancelled.
+pub async fn run(p: &Params, s: &Session, cancel: &CancellationToken) -> color_eyre::Result<()> {
+    let cert = generate_cert()?;
+    let fp = fingerprint(&cert.der);
+
+    // Hold onto the remote listener for the lifetime of this call. When we
+    // return — on cancellation or after an error bubbles up to `main_loop` —
+    // `_remote` drops, closing the remote's stdin, which tells it to shut down
+    // and release `remote` before the next retry tries to bind it.
+    let (quic_port, _remote) = launch_remote(p, s, &cert).await?;
+    let host = destination_host(&p.destination)?;
+
+    let mut endpoint = Endpoint::client((std::net::Ipv4Addr::UNSPECIFIED, 0).into())?;
+    endpoint.set_default_client_config(client_config(fp));
+
+    let span = tracing::info_span!("connecting quic", host = %host, port = quic_port);
+    let addr = lookup(&host, quic_port)?;
+    let conn = endpoint
+        .connect(addr, SERVER_NAME)?
+        .instrument(span)
+        .await?;
+
+    let mode = p.mode;
+    let local = p.local;
+
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => {
+                conn.close(0u32.into(), b"bye");
+                return Ok(());
+            }
+
+            accepted = conn.accept_bi() => {
+                let (send, recv) = accepted?;
+                tokio::spawn(async move {
+                    if let Err(e) = pump(mode, local, send, recv).await {
+                        tracing::debug!(reason = ?e, "quic stream ended");
+                    }
+                });
+            }
+        }
+    }
+}
+
+fn client_config(fingerprint: [u8; 32]) -> ClientConfig {
+    let crypto = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(PinnedCert { fingerprint }))
+        .with_no_client_auth();
+
+    let mut cfg = ClientConfig::new(Arc::new(crypto));
+    cfg.transport_config(Arc::new(keep_alive()));
+    cfg
+}
+
+/// Dial `local` and splice it to a QUIC stream — raw copy for TCP, framed
+/// datagrams for UDP.
+async fn pump(
+    mode: L4Mode,
+    local: SocketAddr,
+    mut send: quinn::SendStream,
+    mut recv: quinn::RecvStream,
+) -> color_eyre::Result<()> {
+    match mode {
+        L4Mode::Tcp => {
+            let stream = tokio::net::TcpStream::connect(local).await?;
+            let (mut rd, mut wr) = stream.into_split();
+
+            let up = tokio::io::copy(&mut recv, &mut wr);
+            let down = tokio::io::copy(&mut rd, &mut send);
+            tokio::try_join!(up, down)?;
+        }
+        L4Mode::Udp => {
+            let sock = tokio::net::UdpSocket::bind(("0.0.0.0", 0)).await?;
+            sock.connect(local).await?;
+
+            let up = async {
+                loop {
+                    let buf = read_datagram(&mut recv).await?;
+                    sock.send(&buf).await?;
+                }
+                #[allow(unreachable_code)]
+                Ok::<(), std::io::Error>(())
+            };
+
+            let down = async {
+                let mut buf = vec![0u8; 64 * 1024];
+                loop {
+                    let n = sock.recv(&mut buf).await?;
+                    write_datagram(&mut send, &buf[..n]).await?;
+                }
+                #[allow(unreachable_code)]
+                Ok::<(), std::io::Error>(())
+            };
+
+            tokio::try_join!(up, down)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Start the listener on the remote via SSH, handing it the cert to serve, and
+/// read back the QUIC port it bound.
+///
+/// The spawned [`RemoteChild`](openssh::RemoteChild) is returned so the caller
+/// keeps it alive; dropping it closes the listener's stdin, which is how the
+/// remote learns to shut itself down (see [`serve`]).
+async fn launch_remote<'s>(
+    p: &Params,
+    s: &'s Session,
+    cert: &Cert,
+) -> color_eyre::Result<(u16, openssh::RemoteChild<'s>)> {
+    let span = tracing::info_span!("launching remote quic listener", remote = p.remote);
+
+    let mut child = s
+        .command("easy_expose")
+        .arg("quic-serve")
+        .arg("--mode")
+        .arg(p.mode.name())
+        .arg("--remote")
+        .arg(p.remote.to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    // Hand the cert + key over stdin so they never hit the process table.
+    let stdin = child
+        .stdin()
+        .as_mut()
+        .wrap_err("Didn't get a stdin for some reason")?;
+    stdin
+        .write_all(format!("{}\0{}\0", cert.cert_pem, cert.key_pem).as_bytes())
+        .await?;
+    stdin.flush().await?;
+
+    // The listener prints the QUIC control port it chose on its first line.
+    let stdout = child
+        .stdout()
+        .as_mut()
+        .wrap_err("Didn't get a stdout for some reason")?;
+    let mut line = Vec::new();
+    loop {
+        let mut byte = [0u8; 1];
+        if stdout.read_exact(&mut byte).instrument(span.clone()).await.is_err() {
+            break;
+        }
+        if byte[0] == b'\n' {
+            break;
+        }
+        line.push(byte[0]);
+    }
+
+    // We deliberately leave the child's stdin open: closing it is the signal
+    // the remote listener waits on to tear down, so the caller hangs onto
+    // `child` and lets it drop when the forward ends.
+    let port = std::str::from_utf8(&line)?.trim().parse()?;
+    Ok((port, child))
+}
+
+fn destination_host(destination: &str) -> color_eyre::Result<String> {
+    let host = destination
+        .rsplit_once('@')
+        .map(|(_, h)| h)
+        .unwrap_or(destination);
+    Ok(host.to_string())
+}
+
+fn lookup(host: &str, port: u16) -> color_eyre::Result<SocketAddr> {
+    use std::net::ToSocketAddrs;
+
+    (host, port)
+        .to_socket_addrs()?
+        .next()
+        .wrap_err("Couldn't resolve remote host")
+}
+
+/// Arguments for the hidden `quic-serve` subcommand that runs on the remote.
+#[derive(clap::Parser, Debug)]
+pub struct ServeArgs {
+    #[clap(long, arg_enum)]
+    mode: L4Mode,
+
+    #[clap(long)]
+    remote: u16,
+}
+
+/// The remote half: accept public traffic on `remote` and fan each connection
+/// out as a QUIC stream to whichever client dialed us.
+///
+/// The client keeps our stdin open for as long as it wants the listener alive;
+/// an EOF means it went away (a crash, or a `main_loop` retry that's about to
+/// relaunch us), so we shut the endpoint down and free `remote` for the next
+/// incarnation. Meanwhile we keep re-accepting QUIC connections so a client
+/// that reconnects transparently takes over the public listener.
+pub async fn serve(args: ServeArgs) -> color_eyre::Result<()> {
+    let mut stdin = tokio::io::stdin();
+    let (cert_pem, key_pem) = read_cert_from_stdin(&mut stdin).await?;
+
+    let cert_chain = rustls_pemfile::certs(&mut cert_pem.as_bytes())?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect();
+    let key = rustls_pemfile::pkcs8_private_keys(&mut key_pem.as_bytes())?
+        .into_iter()
+        .next()
+        .map(rustls::PrivateKey)
+        .wrap_err("No private key in input")?;
+
+    let crypto = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(cert_chain, key)?;
+
+    let mut server_config = ServerConfig::with_crypto(Arc::new(crypto));
+    server_config.transport_config(Arc::new(keep_alive()));
+
+    let endpoint = Endpoint::server(server_config, (std::net::Ipv4Addr::UNSPECIFIED, 0).into())?;
+
+    // Tell the client which port we landed on.
+    println!("{}", endpoint.local_addr()?.port());
+
+    tokio::select! {
+        r = serve_public(&args, &endpoint) => r,
+        _ = wait_for_stdin_eof(&mut stdin) => {
+            tracing::info!("client channel closed; shutting down remote listener");
+            endpoint.close(0u32.into(), b"bye");
+            Ok(())
+        }
+    }
+}
+
+/// Accept public traffic on `remote`, multiplexing onto whichever client is
+/// currently connected. A fresh QUIC connection (a reconnect) replaces the old
+/// one without dropping the public listener.
+async fn serve_public(args: &ServeArgs, endpoint: &Endpoint) -> color_eyre::Result<()> {
+    // The most recently connected client; the public side always dials streams
+    // on whatever connection is live here.
+    let (client_tx, client_rx) = tokio::sync::watch::channel::<Option<Connection>>(None);
+
+    let accept = async move {
+        while let Some(incoming) = endpoint.accept().await {
+            match incoming.await {
+                Ok(conn) => {
+                    tracing::info!("client connected");
+                    let _ = client_tx.send(Some(conn));
+                }
+                Err(e) => tracing::debug!(reason = ?e, "client failed to connect"),
+            }
+        }
+        Ok::<(), color_eyre::Report>(())
+    };
+
+    match args.mode {
+        L4Mode::Tcp => tokio::try_join!(accept, serve_tcp(args.remote, client_rx)).map(|_| ()),
+        L4Mode::Udp => tokio::try_join!(accept, serve_udp(args.remote, client_rx)).map(|_| ()),
+    }
+}
+
+/// Copy each inbound TCP connection to a fresh bi-directional stream on the
+/// current client connection.
+async fn serve_tcp(
+    remote: u16,
+    client: tokio::sync::watch::Receiver<Option<Connection>>,
+) -> color_eyre::Result<()> {
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", remote)).await?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let Some(conn) = client.borrow().clone() else {
+            // No client has connected yet; nothing to forward to.
+            continue;
+        };
+        tokio::spawn(async move {
+            if let Ok((mut send, mut recv)) = conn.open_bi().await {
+                let (mut rd, mut wr) = stream.into_split();
+                let up = tokio::io::copy(&mut rd, &mut send);
+                let down = tokio::io::copy(&mut recv, &mut wr);
+                let _ = tokio::try_join!(up, down);
+            }
+        });
+    }
+}
+
+/// Carry datagrams arriving on `remote` over a single length-prefixed stream to
+/// the current client, re-opening the stream whenever a client (re)connects.
+async fn serve_udp(
+    remote: u16,
+    mut client: tokio::sync::watch::Receiver<Option<Connection>>,
+) -> color_eyre::Result<()> {
+    let sock = Arc::new(tokio::net::UdpSocket::bind(("0.0.0.0", remote)).await?);
+    let peer: Arc<tokio::sync::Mutex<Option<SocketAddr>>> = Arc::new(tokio::sync::Mutex::new(None));
+
+    loop {
+        // Wait for a live client connection.
+        let conn = loop {
+            if let Some(c) = client.borrow_and_update().clone() {
+                break c;
+            }
+            if client.changed().await.is_err() {
+                return Ok(());
+            }
+        };
+
+        let (mut send, mut recv) = match conn.open_bi().await {
+            Ok(v) => v,
+            Err(e) => {
+                tracing::debug!(reason = ?e, "couldn't open stream to client");
+                if client.changed().await.is_err() {
+                    return Ok(());
+                }
+                continue;
+            }
+        };
+
+        let up = {
+            let sock = Arc::clone(&sock);
+            let peer = Arc::clone(&peer);
+            async move {
+                let mut buf = vec![0u8; 64 * 1024];
+                loop {
+                    let (n, from) = sock.recv_from(&mut buf).await?;
+                    *peer.lock().await = Some(from);
+                    write_datagram(&mut send, &buf[..n]).await?;
+                }
+                #[allow(unreachable_code)]
+                Ok::<(), color_eyre::Report>(())
+            }
+        };
+
+        let down = {
+            let sock = Arc::clone(&sock);
+            let peer = Arc::clone(&peer);
+            async move {
+                loop {
+                    let buf = read_datagram(&mut recv).await?;
+                    if let Some(to) = *peer.lock().await {
+                        sock.send_to(&buf, to).await?;
+                    }
+                }
+                #[allow(unreachable_code)]
+                Ok::<(), color_eyre::Report>(())
+            }
+        };
+
+        // Pump until the stream breaks, then loop to pick up a reconnecting
+        // client.
+        if let Err(e) = tokio::try_join!(up, down) {
+            tracing::debug!(reason = ?e, "udp stream ended; awaiting client");
+        }
+    }
+}
+
+/// Resolve once the client closes its end of our stdin (the teardown signal).
+async fn wait_for_stdin_eof<R: AsyncRead + Unpin>(r: &mut R) {
+    let mut buf = [0u8; 256];
+    loop {
+        match r.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+    }
+}
+
+/// Read the NUL-delimited cert and key off stdin, leaving the handle open so
+/// its later EOF can serve as the shutdown signal.
+async fn read_cert_from_stdin<R: AsyncRead + Unpin>(r: &mut R) -> color_eyre::Result<(String, String)> {
+    let mut fields: Vec<String> = Vec::new();
+    let mut cur: Vec<u8> = Vec::new();
+    let mut byte = [0u8; 1];
+
+    while fields.len() < 2 {
+        r.read_exact(&mut byte).await?;
+        if byte[0] == 0 {
+            fields.push(String::from_utf8(std::mem::take(&mut cur))?);
+        } else {
+            cur.push(byte[0]);
+        }
+    }
+
+    let mut it = fields.into_iter();
+    Ok((it.next().unwrap(), it.next().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::client::ServerCertVerifier;
+
+    #[test]
+    fn fingerprint_is_stable_and_distinguishing() {
+        assert_eq!(fingerprint(b"some-cert-der"), fingerprint(b"some-cert-der"));
+        assert_ne!(fingerprint(b"some-cert-der"), fingerprint(b"other-cert-der"));
+    }
+
+    #[test]
+    fn pinned_cert_accepts_only_its_own_fingerprint() {
+        let cert = generate_cert().unwrap();
+        let end_entity = rustls::Certificate(cert.der.clone());
+        let name = rustls::ServerName::try_from(SERVER_NAME).unwrap();
+        let now = std::time::SystemTime::UNIX_EPOCH;
+
+        let pinned = PinnedCert {
+            fingerprint: fingerprint(&cert.der),
+        };
+        assert!(pinned
+            .verify_server_cert(&end_entity, &[], &name, &mut std::iter::empty::<&[u8]>(), &[], now)
+            .is_ok());
+
+        let mismatched = PinnedCert {
+            fingerprint: [0u8; 32],
+        };
+        assert!(mismatched
+            .verify_server_cert(&end_entity, &[], &name, &mut std::iter::empty::<&[u8]>(), &[], now)
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_nul_delimited_cert_and_key() {
+        let mut input = b"CERT-PEM\0KEY-PEM\0trailing".as_slice();
+        let (cert, key) = read_cert_from_stdin(&mut input).await.unwrap();
+        assert_eq!(cert, "CERT-PEM");
+        assert_eq!(key, "KEY-PEM");
+    }
+}